@@ -0,0 +1,341 @@
+//! A streaming CTMP (Custom Transfer Message Protocol) decoder, factored out
+//! of the relay's socket-handling code so the framing logic can be unit
+//! tested and fuzzed independently of any `TcpStream`.
+//!
+//! Feed bytes into a `bytes::BytesMut` as they arrive from `read` (not
+//! `read_exact` — a single read is not guaranteed to line up with message
+//! boundaries) and call [`CtmpCodec::decode`] in a loop: it returns
+//! `Ok(Some(message))` once a full frame is buffered, `Ok(None)` when more
+//! data is needed, or a typed [`CtmpError`]. `BadMagic` and `BadPadding` are
+//! fatal for the connection; `ChecksumMismatch` rejects only that one message,
+//! whose frame has already been consumed from the buffer.
+
+use std::fmt;
+
+use bytes::{Buf, BytesMut};
+
+const MAGIC: u8 = 0xCC;
+const HEADER_SIZE: usize = 8;
+
+/// A parsed CTMP header.
+/// 1 magic byte = 0xCC
+/// 1 byte for the options where bit 1 represents a sensitive message i.e. 0100 0000
+/// 2 bytes for the length of payload (16-bit limit provides implicit max payload size of 65,535 bytes)
+/// 2 bytes for the checksum
+/// 2 bytes of 0s for padding
+#[derive(Debug, Clone, Copy)]
+pub struct Header {
+    magic: u8,
+    options: u8,
+    length: u16,
+    checksum: u16,
+    padding: u16,
+}
+
+impl Header {
+    // Creates a Header struct from an 8-byte buffer
+    fn from_bytes(bytes: &[u8; HEADER_SIZE]) -> Self {
+        let length: u16 = u16::from_be_bytes([bytes[2], bytes[3]]);
+        let checksum: u16 = u16::from_be_bytes([bytes[4], bytes[5]]);
+        let padding: u16 = u16::from_be_bytes([bytes[6], bytes[7]]);
+
+        Header {
+            magic: bytes[0],
+            options: bytes[1],
+            length,
+            checksum,
+            padding,
+        }
+    }
+
+    fn to_bytes(self) -> [u8; HEADER_SIZE] {
+        let length = self.length.to_be_bytes();
+        let checksum = self.checksum.to_be_bytes();
+        let padding = self.padding.to_be_bytes();
+        [
+            self.magic,
+            self.options,
+            length[0],
+            length[1],
+            checksum[0],
+            checksum[1],
+            padding[0],
+            padding[1],
+        ]
+    }
+
+    /// Validates the header fields against CTMP spec
+    /// Magic byte must be 0xCC and both padding bytes should be filled with 0s
+    /// Length is implicitly bounded by size of u16
+    pub fn is_valid(&self) -> bool {
+        self.magic == MAGIC && self.padding == 0
+    }
+
+    pub fn is_sensitive(&self) -> bool {
+        (self.options & 0b0100_0000) != 0
+    }
+
+    /// Returns the payload length as a usize for vec allocation
+    pub fn payload_length(&self) -> usize {
+        self.length as usize
+    }
+
+    // The checksum is calculated by summing all 16 bit words of the entire message
+    // with 0xCCCC as the checksum for calculation
+    // We keep adding the sum until it becomes a 16 bit number
+    // The checksum is then the ones complement of this number
+    // ---- The specification wording is slightly unclear on this but this is my interpretation ------
+    pub fn validate_checksum(&self, data: &[u8]) -> bool {
+        let mut sum: u32 = 0;
+        let mut chunks = data.chunks_exact(2);
+
+        sum += u16::from_be_bytes([self.magic, self.options]) as u32;
+        sum += self.length as u32;
+        sum += 0xCCCC_u32;
+
+        // Sum all 16-bit words
+        for chunk in chunks.by_ref() {
+            let word = u16::from_be_bytes([chunk[0], chunk[1]]);
+            sum += u32::from(word);
+        }
+
+        // If there's an odd byte left, pad it with a zero byte and add to sum
+        if let Some(&last_byte) = chunks.remainder().first() {
+            let word = u16::from_be_bytes([last_byte, 0]);
+            sum += u32::from(word);
+        }
+
+        // Fold the 32-bit sum into 16 bits
+        while (sum >> 16) > 0 {
+            sum = (sum >> 16) + (sum & 0xFFFF);
+        }
+
+        let checksum = !sum as u16;
+
+        checksum == self.checksum
+    }
+}
+
+/// A fully decoded CTMP message: its header plus the payload bytes.
+pub struct Message {
+    pub header: Header,
+    pub payload: Vec<u8>,
+}
+
+impl Message {
+    pub fn is_sensitive(&self) -> bool {
+        self.header.is_sensitive()
+    }
+
+    pub fn validate_checksum(&self) -> bool {
+        self.header.validate_checksum(&self.payload)
+    }
+
+    /// Re-serializes the message to the exact bytes it should be relayed as:
+    /// the 8-byte header followed by the payload.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(HEADER_SIZE + self.payload.len());
+        bytes.extend_from_slice(&self.header.to_bytes());
+        bytes.extend_from_slice(&self.payload);
+        bytes
+    }
+}
+
+/// Errors the codec can hit while decoding a frame. All are fatal to the
+/// connection except `ChecksumMismatch`, which only means this one message
+/// should be dropped (see the CTMP spec's handling of sensitive messages).
+#[derive(Debug)]
+pub enum CtmpError {
+    BadMagic(u8),
+    BadPadding(u16),
+    ChecksumMismatch,
+}
+
+impl fmt::Display for CtmpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CtmpError::BadMagic(got) => {
+                write!(
+                    f,
+                    "invalid CTMP magic byte: expected {MAGIC:#x}, got {got:#x}"
+                )
+            }
+            CtmpError::BadPadding(got) => {
+                write!(f, "invalid CTMP padding: expected 0, got {got:#x}")
+            }
+            CtmpError::ChecksumMismatch => write!(f, "CTMP checksum mismatch on sensitive message"),
+        }
+    }
+}
+
+impl std::error::Error for CtmpError {}
+
+/// Streaming CTMP decoder. Holds no buffering of its own — callers own the
+/// `BytesMut` they accumulate incoming reads into and pass it to `decode` on
+/// each call, so the same codec can be driven by sockets, pipes, or a fuzzer
+/// feeding it arbitrary chunk boundaries.
+#[derive(Default)]
+pub struct CtmpCodec;
+
+impl CtmpCodec {
+    pub fn new() -> Self {
+        CtmpCodec
+    }
+
+    /// Tries to decode one message out of `buf`.
+    ///
+    /// Returns `Ok(None)` without touching `buf` if a full frame isn't
+    /// buffered yet. On `BadMagic`/`BadPadding` the header can't be trusted
+    /// at all, so `buf` is left untouched and the caller should treat the
+    /// connection as unrecoverable. On `ChecksumMismatch` the header was
+    /// structurally valid, so the full frame is still consumed from `buf` —
+    /// only this one message is rejected, and the next call can decode
+    /// whatever follows it.
+    pub fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<Message>, CtmpError> {
+        if buf.len() < HEADER_SIZE {
+            return Ok(None);
+        }
+
+        let mut header_bytes = [0u8; HEADER_SIZE];
+        header_bytes.copy_from_slice(&buf[..HEADER_SIZE]);
+        let header = Header::from_bytes(&header_bytes);
+
+        if !header.is_valid() {
+            if header.magic != MAGIC {
+                return Err(CtmpError::BadMagic(header.magic));
+            }
+            return Err(CtmpError::BadPadding(header.padding));
+        }
+
+        let total_len = HEADER_SIZE + header.payload_length();
+        if buf.len() < total_len {
+            return Ok(None);
+        }
+
+        buf.advance(HEADER_SIZE);
+        let payload = buf.split_to(header.payload_length()).to_vec();
+        let message = Message { header, payload };
+
+        if message.is_sensitive() && !message.validate_checksum() {
+            return Err(CtmpError::ChecksumMismatch);
+        }
+
+        Ok(Some(message))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // magic=0xCC, options=0x40 (sensitive), payload=b"hi", checksum=0xFE86
+    const SENSITIVE_MESSAGE: &[u8] = &[0xCC, 0x40, 0x00, 0x02, 0xFE, 0x86, 0x00, 0x00, b'h', b'i'];
+    const PLAIN_MESSAGE: &[u8] = &[0xCC, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, 0x00, b'h', b'i'];
+
+    #[test]
+    fn decode_returns_none_until_a_full_header_is_buffered() {
+        let mut codec = CtmpCodec::new();
+        let mut buf = BytesMut::from(&PLAIN_MESSAGE[..4]);
+        assert!(codec.decode(&mut buf).unwrap().is_none());
+    }
+
+    #[test]
+    fn decode_returns_none_until_the_full_payload_is_buffered() {
+        let mut codec = CtmpCodec::new();
+        let mut buf = BytesMut::from(&PLAIN_MESSAGE[..9]);
+        assert!(codec.decode(&mut buf).unwrap().is_none());
+    }
+
+    #[test]
+    fn decode_parses_a_complete_message_delivered_in_one_chunk() {
+        let mut codec = CtmpCodec::new();
+        let mut buf = BytesMut::from(PLAIN_MESSAGE);
+        let message = codec.decode(&mut buf).unwrap().expect("complete message");
+        assert_eq!(message.payload, b"hi");
+        assert!(!message.is_sensitive());
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn decode_parses_a_message_fed_in_arbitrary_chunks() {
+        let mut codec = CtmpCodec::new();
+        let mut buf = BytesMut::new();
+
+        for byte in PLAIN_MESSAGE {
+            buf.extend_from_slice(&[*byte]);
+            if let Some(message) = codec.decode(&mut buf).unwrap() {
+                assert_eq!(message.payload, b"hi");
+                return;
+            }
+        }
+        panic!("message was never fully decoded");
+    }
+
+    #[test]
+    fn decode_leaves_a_second_message_buffered_for_the_next_call() {
+        let mut codec = CtmpCodec::new();
+        let mut buf = BytesMut::new();
+        buf.extend_from_slice(PLAIN_MESSAGE);
+        buf.extend_from_slice(PLAIN_MESSAGE);
+
+        assert!(codec.decode(&mut buf).unwrap().is_some());
+        let second = codec.decode(&mut buf).unwrap().expect("second message");
+        assert_eq!(second.payload, b"hi");
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn decode_rejects_bad_magic() {
+        let mut codec = CtmpCodec::new();
+        let mut bytes = PLAIN_MESSAGE.to_vec();
+        bytes[0] = 0xAB;
+        let mut buf = BytesMut::from(&bytes[..]);
+        assert!(matches!(
+            codec.decode(&mut buf),
+            Err(CtmpError::BadMagic(0xAB))
+        ));
+    }
+
+    #[test]
+    fn decode_rejects_nonzero_padding() {
+        let mut codec = CtmpCodec::new();
+        let mut bytes = PLAIN_MESSAGE.to_vec();
+        bytes[6] = 0x01;
+        let mut buf = BytesMut::from(&bytes[..]);
+        assert!(matches!(
+            codec.decode(&mut buf),
+            Err(CtmpError::BadPadding(0x0100))
+        ));
+    }
+
+    #[test]
+    fn decode_accepts_a_valid_sensitive_checksum() {
+        let mut codec = CtmpCodec::new();
+        let mut buf = BytesMut::from(SENSITIVE_MESSAGE);
+        let message = codec.decode(&mut buf).unwrap().expect("complete message");
+        assert!(message.is_sensitive());
+        assert!(message.validate_checksum());
+    }
+
+    #[test]
+    fn decode_rejects_a_bad_sensitive_checksum_but_still_consumes_the_frame() {
+        let mut codec = CtmpCodec::new();
+        let mut bytes = SENSITIVE_MESSAGE.to_vec();
+        bytes[4] ^= 0xFF; // corrupt the checksum field
+        let mut buf = BytesMut::from(&bytes[..]);
+
+        assert!(matches!(
+            codec.decode(&mut buf),
+            Err(CtmpError::ChecksumMismatch)
+        ));
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn message_round_trips_to_the_original_bytes() {
+        let mut codec = CtmpCodec::new();
+        let mut buf = BytesMut::from(PLAIN_MESSAGE);
+        let message = codec.decode(&mut buf).unwrap().expect("complete message");
+        assert_eq!(message.to_bytes(), PLAIN_MESSAGE);
+    }
+}
@@ -1,155 +1,600 @@
+mod codec;
+mod tls;
+
+use std::collections::VecDeque;
 use std::io::{self, Read, Write};
+use std::net::SocketAddr;
 use std::net::{TcpListener, TcpStream};
-use std::sync::{Arc, Mutex};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
+
+use bytes::BytesMut;
+use rustls::{ServerConfig, ServerConnection, StreamOwned};
 
-const SOURCE_ADDR: &str = "127.0.0.1:33333";
-const DESTINATION_ADDR: &str = "127.0.0.1:44444";
-const MAGIC: u8 = 0xCC;
-const HEADER_SIZE: usize = 8;
+use codec::{CtmpCodec, CtmpError};
+
+const SOURCE_ADDR: &str = "tcp:127.0.0.1:33333";
+const DESTINATION_ADDR: &str = "tcp:127.0.0.1:44444";
+const SOURCE_ADDR_ENV: &str = "WIRESTORM_SOURCE_ADDR";
+const DESTINATION_ADDR_ENV: &str = "WIRESTORM_DESTINATION_ADDR";
 const MAX_DESTINATIONS: usize = 100; // Prevents number of destination clients potentially overwhelming server
 const READ_TIMEOUT_SECS: u64 = 10; // Prevent slow clients holding connection indefinitely
+const QUEUE_DEPTH_ENV: &str = "WIRESTORM_QUEUE_DEPTH";
+const DEFAULT_QUEUE_DEPTH: usize = 256; // Per-destination outstanding message budget before the drop policy kicks in
+const DROP_POLICY_ENV: &str = "WIRESTORM_DROP_POLICY"; // "drop-oldest" or "disconnect-client"
+const DEFAULT_DROP_POLICY: DropPolicy = DropPolicy::DropOldest; // What to do when a destination can't keep up
+const METRICS_RATE_WINDOW: Duration = Duration::from_secs(10); // Sliding window for the reported bytes/sec rate
+const METRICS_LOG_INTERVAL: Duration = Duration::from_secs(30); // How often per-destination counters are logged
+const MANAGED_DESTINATIONS_ENV: &str = "WIRESTORM_MANAGED_DESTINATIONS"; // Comma-separated "ip:port" list
+const MANAGED_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+const MANAGED_BACKOFF_INITIAL: Duration = Duration::from_secs(1);
+const MANAGED_BACKOFF_MAX: Duration = Duration::from_secs(30);
+const MANAGED_HEALTH_POLL_INTERVAL: Duration = Duration::from_millis(500);
+const WRITE_TIMEOUT_ENV: &str = "WIRESTORM_WRITE_TIMEOUT_SECS";
+const WRITE_TIMEOUT_SECS: u64 = 5; // A wedged destination that never drains its socket gets evicted after this long
+
+// ============== Transport: TCP or Unix domain sockets ================
+
+/// Where to listen, parsed from a `scheme:value` string such as
+/// `tcp:127.0.0.1:33333` or `unix:/run/wirestorm-source.sock`. Lets the
+/// source and destination endpoints each independently choose TCP or a local
+/// Unix domain socket, configured via `WIRESTORM_SOURCE_ADDR` /
+/// `WIRESTORM_DESTINATION_ADDR`.
+enum ListenAddr {
+    Tcp(String),
+    Unix(String),
+}
 
-// ============= CTMP Header structure ============
-
-/// Represents a parsed CTMP message header
-/// 1 magic byte = 0xCC
-/// 1 byte for the options where bit 1 represents a sensitive message i.e. 0100 0000
-/// 2 bytes for the length of payload (16-bit limit provides implicit max payload size of 65,535 bytes)
-/// 2 bytes for the checksum
-/// 2 bytes of 0s for padding
-struct Header {
-    magic: u8,
-    options: u8,
-    length: u16,
-    checksum: u16,
-    padding: u16,
-}
-
-impl Header {
-    // Creates a Header struct from an 8-byte buffer
-    pub fn from_bytes(bytes: &[u8; HEADER_SIZE]) -> Self {
-        let length: u16 = u16::from_be_bytes([bytes[2], bytes[3]]);
-        let checksum: u16 = u16::from_be_bytes([bytes[4], bytes[5]]);
-        let padding: u16 = u16::from_be_bytes([bytes[6], bytes[7]]);
-
-        Header {
-            magic: bytes[0],
-            options: bytes[1],
-            length,
-            checksum,
-            padding,
+impl ListenAddr {
+    fn parse(spec: &str) -> io::Result<Self> {
+        match spec.split_once(':') {
+            Some(("tcp", addr)) => Ok(ListenAddr::Tcp(addr.to_string())),
+            Some(("unix", path)) => Ok(ListenAddr::Unix(path.to_string())),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("'{spec}' is not a valid listen address (expected 'tcp:<addr>' or 'unix:<path>')"),
+            )),
         }
     }
 
-    /// Validates the header fields against CTMP spec
-    /// Magic byte must be 0xCC and both padding bytes should be filled with 0s
-    /// Length is implicitly bounded by size of u16
-    pub fn is_valid(&self) -> bool {
-        self.magic == MAGIC && self.padding == 0
+    fn from_env_or(env_var: &str, default: &str) -> io::Result<Self> {
+        let spec = std::env::var(env_var).unwrap_or_else(|_| default.to_string());
+        Self::parse(&spec)
     }
+}
+
+/// A bound listener, abstracting over TCP and Unix domain sockets so the rest
+/// of the server only deals in `Conn` values and never cares which transport
+/// produced them.
+enum Endpoint {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
 
-    pub fn is_sensitive(&self) -> bool {
-        (self.options & 0b0100_0000) != 0
+impl Endpoint {
+    fn bind(addr: &ListenAddr) -> io::Result<Self> {
+        match addr {
+            ListenAddr::Tcp(addr) => Ok(Endpoint::Tcp(TcpListener::bind(addr)?)),
+            ListenAddr::Unix(path) => {
+                // A stale socket file from a previous run would otherwise make
+                // bind() fail with AddrInUse even though nothing is listening.
+                let _ = std::fs::remove_file(path);
+                Ok(Endpoint::Unix(UnixListener::bind(path)?))
+            }
+        }
     }
 
-    /// Returns the payload length as a usize for vec allocation
-    pub fn payload_length(&self) -> usize {
-        self.length as usize
+    /// Accepts the next connection, returning the transport-agnostic stream
+    /// along with a human-readable peer address for logging.
+    fn accept(&self) -> io::Result<(Conn, String)> {
+        match self {
+            Endpoint::Tcp(listener) => {
+                let (stream, addr) = listener.accept()?;
+                Ok((Conn::Tcp(stream), addr.to_string()))
+            }
+            Endpoint::Unix(listener) => {
+                let (stream, addr) = listener.accept()?;
+                let address = addr
+                    .as_pathname()
+                    .map(|p| p.display().to_string())
+                    .unwrap_or_else(|| String::from("unix:unnamed"));
+                Ok((Conn::Unix(stream), address))
+            }
+        }
     }
+}
 
-    // The checksum is calculated by summing all 16 bit words of the entire message
-    // with 0xCCCC as the checksum for calculation
-    // We keep adding the sum until it becomes a 16 bit number
-    // The checksum is then the ones complement of this number
-    // ---- The specification wording is slightly unclear on this but this is my interpretation ------
-    pub fn validate_checksum(&self, data: &[u8]) -> bool {
-        let mut sum: u32 = 0;
-        let mut chunks = data.chunks_exact(2);
+/// A connected stream from either transport. CTMP framing, TLS wrapping and
+/// the relay logic all operate on `Read + Write` and never need to know which
+/// variant they were handed.
+enum Conn {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
 
-        sum += u16::from_be_bytes([self.magic, self.options]) as u32;
-        sum += self.length as u32;
-        sum += 0xCCCC_u32;
+impl Conn {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Conn::Tcp(stream) => stream.set_read_timeout(timeout),
+            Conn::Unix(stream) => stream.set_read_timeout(timeout),
+        }
+    }
+}
 
-        // Sum all 16-bit words
-        for chunk in chunks.by_ref() {
-            let word = u16::from_be_bytes([chunk[0], chunk[1]]);
-            sum += u32::from(word);
+impl Read for Conn {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Conn::Tcp(stream) => stream.read(buf),
+            Conn::Unix(stream) => stream.read(buf),
         }
+    }
+}
 
-        // If there's an odd byte left, pad it with a zero byte and add to sum
-        if let Some(&last_byte) = chunks.remainder().first() {
-            let word = u16::from_be_bytes([last_byte, 0]);
-            sum += u32::from(word);
+impl Write for Conn {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Conn::Tcp(stream) => stream.write(buf),
+            Conn::Unix(stream) => stream.write(buf),
         }
+    }
 
-        // Fold the 32-bit sum into 16 bits
-        while (sum >> 16) > 0 {
-            sum = (sum >> 16) + (sum & 0xFFFF);
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Conn::Tcp(stream) => stream.flush(),
+            Conn::Unix(stream) => stream.flush(),
         }
+    }
+}
+
+/// Lets `Destination::spawn` bound whatever transport it's given so a
+/// wedged destination can be evicted instead of blocking its writer thread
+/// forever. `Read + Write` alone doesn't expose socket timeouts, so this is a
+/// small extra bound.
+///
+/// Both a write *and* a read timeout are needed even though the writer
+/// thread only ever calls `write_all`: for a TLS destination, `StreamOwned`'s
+/// `write()` completes any outstanding handshake I/O first, which includes
+/// *reading* the client's ClientHello off the raw socket. Without a read
+/// timeout, a destination that completes the TCP accept but never speaks TLS
+/// hangs the writer thread on that read, write timeout notwithstanding.
+trait SetSocketTimeouts {
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+}
 
-        let checksum = !sum as u16;
+impl SetSocketTimeouts for TcpStream {
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_write_timeout(self, timeout)
+    }
 
-        checksum == self.checksum
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
     }
 }
 
+impl SetSocketTimeouts for UnixStream {
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        UnixStream::set_write_timeout(self, timeout)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        UnixStream::set_read_timeout(self, timeout)
+    }
+}
+
+impl SetSocketTimeouts for Conn {
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Conn::Tcp(stream) => SetSocketTimeouts::set_write_timeout(stream, timeout),
+            Conn::Unix(stream) => SetSocketTimeouts::set_write_timeout(stream, timeout),
+        }
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Conn::Tcp(stream) => SetSocketTimeouts::set_read_timeout(stream, timeout),
+            Conn::Unix(stream) => SetSocketTimeouts::set_read_timeout(stream, timeout),
+        }
+    }
+}
+
+impl<S: SetSocketTimeouts + Read + Write> SetSocketTimeouts for StreamOwned<ServerConnection, S> {
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.sock.set_write_timeout(timeout)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.sock.set_read_timeout(timeout)
+    }
+}
+
+// ============== Destination fan-out ================
+
+/// What to do with a destination that can't drain its queue fast enough.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum DropPolicy {
+    /// Drop the oldest queued message to make room for the new one, keeping the client connected.
+    DropOldest,
+    /// Evict the client outright rather than let it fall further behind.
+    DisconnectClient,
+}
+
+impl DropPolicy {
+    fn parse(s: &str) -> Option<Self> {
+        match s {
+            "drop-oldest" => Some(DropPolicy::DropOldest),
+            "disconnect-client" => Some(DropPolicy::DisconnectClient),
+            _ => None,
+        }
+    }
+
+    /// Reads `WIRESTORM_DROP_POLICY`, falling back to `DEFAULT_DROP_POLICY`
+    /// if unset or invalid.
+    fn from_env() -> Self {
+        std::env::var(DROP_POLICY_ENV)
+            .ok()
+            .and_then(|value| Self::parse(&value))
+            .unwrap_or(DEFAULT_DROP_POLICY)
+    }
+}
+
+/// Reads `WIRESTORM_QUEUE_DEPTH`, falling back to `DEFAULT_QUEUE_DEPTH` if
+/// unset or invalid.
+fn queue_depth_from_env() -> usize {
+    std::env::var(QUEUE_DEPTH_ENV)
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(DEFAULT_QUEUE_DEPTH)
+}
+
+/// Per-destination throughput and health counters, logged periodically so the
+/// relay's behaviour under load is visible instead of only inferable from
+/// connect/disconnect log lines.
+struct Metrics {
+    bytes_relayed: AtomicU64,
+    messages_dropped: AtomicU64,
+    recent_writes: Mutex<VecDeque<(Instant, u64)>>,
+}
+
+impl Metrics {
+    fn new() -> Self {
+        Metrics {
+            bytes_relayed: AtomicU64::new(0),
+            messages_dropped: AtomicU64::new(0),
+            recent_writes: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    fn record_write(&self, bytes: usize) {
+        self.bytes_relayed
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+        let mut recent = self.recent_writes.lock().unwrap();
+        recent.push_back((Instant::now(), bytes as u64));
+        Self::trim_window(&mut recent);
+    }
+
+    fn record_drop(&self) {
+        self.messages_dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn trim_window(recent: &mut VecDeque<(Instant, u64)>) {
+        let cutoff = Instant::now()
+            .checked_sub(METRICS_RATE_WINDOW)
+            .unwrap_or(Instant::now());
+        while matches!(recent.front(), Some((t, _)) if *t < cutoff) {
+            recent.pop_front();
+        }
+    }
+
+    /// Returns `(total bytes relayed, messages dropped, bytes/sec over the
+    /// trailing METRICS_RATE_WINDOW)`.
+    fn snapshot(&self) -> (u64, u64, f64) {
+        let mut recent = self.recent_writes.lock().unwrap();
+        Self::trim_window(&mut recent);
+        let windowed_bytes: u64 = recent.iter().map(|(_, bytes)| bytes).sum();
+        let rate = windowed_bytes as f64 / METRICS_RATE_WINDOW.as_secs_f64();
+        (
+            self.bytes_relayed.load(Ordering::Relaxed),
+            self.messages_dropped.load(Ordering::Relaxed),
+            rate,
+        )
+    }
+}
+
+/// A bounded, drop-aware queue of outbound messages for a single destination.
+///
+/// We can't use `std::sync::mpsc`'s bounded channel here because it blocks the
+/// sender on a full queue, and `try_send` only ever gives us "full, drop the new
+/// message" semantics. A `DropPolicy::DropOldest` queue needs to evict from the
+/// front instead, so we roll a small Mutex + Condvar deque ourselves.
+struct DestinationQueue {
+    state: Mutex<VecDeque<Arc<Vec<u8>>>>,
+    not_empty: Condvar,
+    capacity: usize,
+}
+
+impl DestinationQueue {
+    fn new(capacity: usize) -> Self {
+        DestinationQueue {
+            state: Mutex::new(VecDeque::with_capacity(capacity)),
+            not_empty: Condvar::new(),
+            capacity,
+        }
+    }
+
+    /// Enqueues `message` according to `policy`. Returns `false` if the client
+    /// should be disconnected instead (queue was full and the policy says so).
+    /// Either outcome that drops a message is reflected in `metrics`.
+    fn push(&self, policy: DropPolicy, message: Arc<Vec<u8>>, metrics: &Metrics) -> bool {
+        let mut queue = self.state.lock().unwrap();
+        if queue.len() >= self.capacity {
+            match policy {
+                DropPolicy::DropOldest => {
+                    queue.pop_front();
+                    metrics.record_drop();
+                }
+                DropPolicy::DisconnectClient => {
+                    metrics.record_drop();
+                    return false;
+                }
+            }
+        }
+        queue.push_back(message);
+        self.not_empty.notify_one();
+        true
+    }
+
+    /// Blocks the calling (worker) thread until a message is available or the
+    /// timeout elapses, returning `None` on timeout so the worker can re-check
+    /// whether it has been asked to shut down.
+    fn pop(&self, timeout: Duration) -> Option<Arc<Vec<u8>>> {
+        let mut queue = self.state.lock().unwrap();
+        loop {
+            if let Some(message) = queue.pop_front() {
+                return Some(message);
+            }
+            let (guard, result) = self.not_empty.wait_timeout(queue, timeout).unwrap();
+            queue = guard;
+            if result.timed_out() && queue.is_empty() {
+                return None;
+            }
+        }
+    }
+}
+
+/// A registered destination client: a bounded queue feeding a dedicated writer
+/// thread, plus a flag the writer flips when the underlying stream dies so the
+/// source loop can reap it without touching the socket itself.
+struct Destination {
+    address: String,
+    queue: Arc<DestinationQueue>,
+    alive: Arc<AtomicBool>,
+    metrics: Arc<Metrics>,
+    drop_policy: DropPolicy,
+}
+
+impl Destination {
+    /// Spawns the writer thread for a newly accepted destination `stream` and
+    /// returns the handle the source loop will publish messages through.
+    ///
+    /// Generic over `Read + Write` rather than tied to `TcpStream` so the same
+    /// writer loop works whether the destination is plaintext or wrapped in a
+    /// TLS session.
+    fn spawn<S: Read + Write + Send + SetSocketTimeouts + 'static>(
+        stream: S,
+        address: String,
+    ) -> Self {
+        let queue = Arc::new(DestinationQueue::new(queue_depth_from_env()));
+        let alive = Arc::new(AtomicBool::new(true));
+        let metrics = Arc::new(Metrics::new());
+        let drop_policy = DropPolicy::from_env();
+
+        let write_timeout = write_timeout_from_env();
+        if let Err(e) = stream.set_write_timeout(Some(write_timeout)) {
+            eprintln!(
+                "Warning: could not set write timeout for destination {address}: {e}. A wedged socket may block its queue."
+            );
+        }
+        // For a TLS destination, write() also completes any outstanding
+        // handshake I/O first, which reads the ClientHello off the raw
+        // socket — so a read timeout is needed too, or a destination that
+        // never speaks TLS can still wedge the writer thread indefinitely.
+        if let Err(e) = stream.set_read_timeout(Some(write_timeout)) {
+            eprintln!(
+                "Warning: could not set read timeout for destination {address}: {e}. A wedged TLS handshake may block its queue."
+            );
+        }
+
+        let worker_queue = Arc::clone(&queue);
+        let worker_alive = Arc::clone(&alive);
+        let worker_metrics = Arc::clone(&metrics);
+        let worker_address = address.clone();
+        let mut stream = stream;
+        thread::spawn(move || {
+            // Poll on a timeout rather than blocking forever so a destination that
+            // was evicted for falling behind (no more messages will ever arrive)
+            // still notices `alive` was cleared and exits instead of leaking.
+            while worker_alive.load(Ordering::Relaxed) {
+                match worker_queue.pop(Duration::from_secs(1)) {
+                    Some(message) => {
+                        if let Err(e) = stream.write_all(&message) {
+                            match e.kind() {
+                                io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => println!(
+                                    "Destination client {worker_address} did not drain its socket within {write_timeout:?}, evicting."
+                                ),
+                                _ => println!(
+                                    "Destination client {worker_address} disconnected during broadcast ({e}), removing from list."
+                                ),
+                            }
+                            worker_alive.store(false, Ordering::Relaxed);
+                            break;
+                        }
+                        worker_metrics.record_write(message.len());
+                    }
+                    None => continue,
+                }
+            }
+        });
+
+        Destination {
+            address,
+            queue,
+            alive,
+            metrics,
+            drop_policy,
+        }
+    }
+
+    fn is_alive(&self) -> bool {
+        self.alive.load(Ordering::Relaxed)
+    }
+
+    /// A clone of the liveness flag, used by managed destinations to notice
+    /// when this instance has died and needs to be redialed.
+    fn alive_handle(&self) -> Arc<AtomicBool> {
+        Arc::clone(&self.alive)
+    }
+
+    /// Queues `message` for this destination, applying its configured drop policy.
+    /// Returns `false` if the destination should be evicted.
+    fn enqueue(&self, message: &Arc<Vec<u8>>) -> bool {
+        if self
+            .queue
+            .push(self.drop_policy, Arc::clone(message), &self.metrics)
+        {
+            true
+        } else {
+            println!(
+                "Destination client {} queue full, evicting under DisconnectClient policy.",
+                self.address
+            );
+            self.alive.store(false, Ordering::Relaxed);
+            false
+        }
+    }
+
+    /// Logs this destination's throughput and drop counters.
+    fn log_metrics(&self) {
+        let (bytes_relayed, messages_dropped, rate) = self.metrics.snapshot();
+        println!(
+            "[metrics] destination {}: {bytes_relayed} bytes relayed, {messages_dropped} messages dropped, {rate:.1} bytes/sec",
+            self.address
+        );
+    }
+}
 
 // ============== Main Server Logic ================
 
 fn main() -> io::Result<()> {
     println!("Starting CTMP Proxy Server...");
 
+    // Either `None` (plaintext) or a loaded TLS server config, depending on
+    // whether WIRESTORM_TLS_CERT/WIRESTORM_TLS_KEY are set in the environment.
+    let tls_config = tls::server_config_from_env()?;
+    if tls_config.is_some() {
+        println!("TLS enabled: source and destination sockets will require a TLS handshake.");
+    }
+
     // This list of destination clients must be shared across multiple threads
-    // so we store in a Vec and wrap it in an Arc Mutex
-    let destinations: Arc<Mutex<Vec<TcpStream>>> =
+    // so we store in a Vec and wrap it in an Arc Mutex. Each entry owns a
+    // dedicated writer thread, so a slow destination only ever stalls its own
+    // queue, never the broadcast loop or the other destinations.
+    let destinations: Arc<Mutex<Vec<Destination>>> =
         Arc::new(Mutex::new(Vec::with_capacity(MAX_DESTINATIONS)));
 
     // ------------- Destination listener threads  ------------------
     let destinations_clone = Arc::clone(&destinations);
-    let dest_listener = TcpListener::bind(DESTINATION_ADDR)?;
-    println!("Listening for destination clients on {DESTINATION_ADDR}");
+    let dest_addr = ListenAddr::from_env_or(DESTINATION_ADDR_ENV, DESTINATION_ADDR)?;
+    let dest_listener = Endpoint::bind(&dest_addr)?;
+    println!("Listening for destination clients");
 
     // Spawn a dedicated thread to accept destination clients
     // This runs concurrently with the source client listener
-    thread::spawn(move || {
-        for stream in dest_listener.incoming() {
-            match stream {
-                Ok(stream) => {
-                    let mut address = String::from("unknown");
-                    if let Ok(addr) = stream.peer_addr() {
-                        address = addr.to_string();
+    let dest_tls_config = tls_config.clone();
+    thread::spawn(move || loop {
+        match dest_listener.accept() {
+            Ok((stream, address)) => match &dest_tls_config {
+                None => register_destination(&destinations_clone, stream, address),
+                Some(tls_config) => match accept_tls(stream, Arc::clone(tls_config), &address) {
+                    Ok(tls_stream) => {
+                        register_destination(&destinations_clone, tls_stream, address)
                     }
-                    let mut dests = destinations_clone.lock().unwrap();
-                    if dests.len() >= MAX_DESTINATIONS {
-                        println!(
-                            "Max destination clients reached. Rejecting new connection from {address}."
-                        );
-                    } else {
-                        println!("New destination client connected: {address}");
-                        dests.push(stream);
-                    }
-                }
-                Err(e) => eprintln!("Error accepting destination client: {e}"),
-            }
+                    Err(e) => eprintln!("TLS handshake failed for destination {address}: {e}"),
+                },
+            },
+            Err(e) => eprintln!("Error accepting destination client: {e}"),
+        }
+    });
+
+    // ------------- Managed (actively-dialed) destinations  ------------------
+    // Unlike accepted destinations, these are fixed downstream consumers the
+    // relay itself connects out to, and resyncs with backoff if the
+    // connection ever drops instead of just discarding the slot.
+    for addr in managed_destination_addrs_from_env() {
+        spawn_managed_destination(addr, Arc::clone(&destinations));
+    }
+
+    // ------------- Periodic metrics logging + reaping  ------------------
+    // Dead destinations are otherwise only pruned by relay_message's retain,
+    // which never runs while no source traffic is flowing. A managed
+    // destination that flaps with no source connected would otherwise pile up
+    // dead entries forever, so this loop also owns reaping them.
+    let metrics_destinations = Arc::clone(&destinations);
+    thread::spawn(move || loop {
+        thread::sleep(METRICS_LOG_INTERVAL);
+        let mut dests = metrics_destinations.lock().unwrap();
+        dests.retain(Destination::is_alive);
+        for dest in dests.iter() {
+            dest.log_metrics();
         }
     });
 
     // -------------- Source listener thread   -----------------
-    let source_listener = TcpListener::bind(SOURCE_ADDR)?;
-    println!("Listening for single source client on {SOURCE_ADDR}");
+    let source_addr = ListenAddr::from_env_or(SOURCE_ADDR_ENV, SOURCE_ADDR)?;
+    let source_listener = Endpoint::bind(&source_addr)?;
+    println!("Listening for single source client");
 
     // Loop to handle one source client at a time
     // When a source disconnects, we wait for the next one
     loop {
         match source_listener.accept() {
-            Ok((stream, addr)) => {
-                println!("Source client connected from: {addr}");
+            Ok((stream, address)) => {
+                println!("Source client connected from: {address}");
+
+                // Set on the raw transport before any TLS wrapping, since
+                // StreamOwned just delegates reads/writes to the socket below.
+                let timeout = Some(Duration::from_secs(READ_TIMEOUT_SECS));
+                if let Err(e) = stream.set_read_timeout(timeout) {
+                    eprintln!(
+                        "Warning: Could not set read timeout for source {address}: {e}. Closing connection."
+                    );
+                    continue;
+                }
+
                 // Handle single source client in the main thread
                 // This blocks until the client disconnects
-                handle_source_client(stream, Arc::clone(&destinations));
-                println!("Source client {addr} disconnected. Waiting for next source client...");
+                match &tls_config {
+                    None => {
+                        handle_source_client(stream, address.clone(), Arc::clone(&destinations))
+                    }
+                    Some(tls_config) => {
+                        match accept_tls(stream, Arc::clone(tls_config), &address) {
+                            Ok(tls_stream) => handle_source_client(
+                                tls_stream,
+                                address.clone(),
+                                Arc::clone(&destinations),
+                            ),
+                            Err(e) => {
+                                eprintln!("TLS handshake failed for source {address}: {e}");
+                                continue;
+                            }
+                        }
+                    }
+                }
+                println!("Source client {address} disconnected. Waiting for next source client...");
             }
             Err(e) => {
                 eprintln!("Error accepting source client: {e}");
@@ -160,89 +605,176 @@ fn main() -> io::Result<()> {
     }
 }
 
-// -------------- Handle source ----------------
+/// Wraps a freshly accepted stream in a rustls server session. The handshake
+/// itself happens lazily on first read/write of the returned `StreamOwned`,
+/// so this just constructs the session. Generic over the transport so it
+/// works uniformly over TCP and Unix domain sockets.
+fn accept_tls<S: Read + Write>(
+    stream: S,
+    config: Arc<ServerConfig>,
+    address: &str,
+) -> io::Result<StreamOwned<ServerConnection, S>> {
+    let conn = ServerConnection::new(config)
+        .map_err(|e| io::Error::other(format!("could not start TLS session for {address}: {e}")))?;
+    Ok(StreamOwned::new(conn, stream))
+}
 
-// Handles a source client connection
-fn handle_source_client(mut stream: TcpStream, destinations: Arc<Mutex<Vec<TcpStream>>>) {
-    // We must handle case where the address is not found properly as peer_addr() returns result
-    let mut address = String::from("unknown");
-    if let Ok(addr) = stream.peer_addr() {
-        address = addr.to_string();
-    }
-    // Similarly here with set_read_timeout()
-    let timeout = Some(Duration::from_secs(READ_TIMEOUT_SECS));
-    if let Err(e) = stream.set_read_timeout(timeout) {
-        eprintln!(
-            "Warning: Could not set read timeout for source {address}: {e}. Closing connection."
-        );
-        return;
-    }
+/// Reads the destination write deadline from `WIRESTORM_WRITE_TIMEOUT_SECS`,
+/// falling back to `WRITE_TIMEOUT_SECS` if unset or invalid.
+fn write_timeout_from_env() -> Duration {
+    std::env::var(WRITE_TIMEOUT_ENV)
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .map(Duration::from_secs)
+        .unwrap_or(Duration::from_secs(WRITE_TIMEOUT_SECS))
+}
 
-    println!("Now handling messages from source client: {address}");
+/// Registers a newly accepted destination stream, enforcing `MAX_DESTINATIONS`.
+fn register_destination<S: Read + Write + Send + SetSocketTimeouts + 'static>(
+    destinations: &Arc<Mutex<Vec<Destination>>>,
+    stream: S,
+    address: String,
+) {
+    let mut dests = destinations.lock().unwrap();
+    if dests.len() >= MAX_DESTINATIONS {
+        println!("Max destination clients reached. Rejecting new connection from {address}.");
+    } else {
+        println!("New destination client connected: {address}");
+        dests.push(Destination::spawn(stream, address));
+    }
+}
 
-    // Loop and read messages from the source client
-    loop {
-        let mut header_buf = [0u8; HEADER_SIZE];
-        match stream.read_exact(&mut header_buf) {
-            Ok(_) => {
-                let header = Header::from_bytes(&header_buf);
-                // Validate ctmp header
-                // Note that we could continue to keep this source open for more messages
-                // but I think it makes sense to just break the connection when considered faulty
-                // or if the specification says otherwise
-                if !header.is_valid() {
-                    eprintln!("Invalid CTMP header from source {address}. Disconnecting.");
-                    break;
-                }
+/// Parses `WIRESTORM_MANAGED_DESTINATIONS` into a list of `ip:port`
+/// addresses to actively dial, or an empty list if it isn't set. Host names
+/// are not resolved: `dial_managed` rejects anything that isn't a socket address.
+fn managed_destination_addrs_from_env() -> Vec<String> {
+    std::env::var(MANAGED_DESTINATIONS_ENV)
+        .map(|value| {
+            value
+                .split(',')
+                .map(str::trim)
+                .filter(|addr| !addr.is_empty())
+                .map(str::to_string)
+                .collect()
+        })
+        .unwrap_or_default()
+}
 
-                let payload_len = header.payload_length();
-                let mut payload_buf = vec![0; payload_len];
+/// Dials and maintains a single managed destination for the lifetime of the
+/// server: connect, register as a normal destination, wait for its writer
+/// thread to mark it dead, then reconnect with exponential backoff.
+fn spawn_managed_destination(addr: String, destinations: Arc<Mutex<Vec<Destination>>>) {
+    thread::spawn(move || {
+        let mut backoff = MANAGED_BACKOFF_INITIAL;
+        loop {
+            match dial_managed(&addr) {
+                Ok(stream) => {
+                    backoff = MANAGED_BACKOFF_INITIAL;
 
-                // Read payload into buffer
-                if let Err(e) = stream.read_exact(&mut payload_buf) {
+                    // Managed destinations share the same MAX_DESTINATIONS
+                    // budget as accepted ones, same as register_destination.
+                    let mut dests = destinations.lock().unwrap();
+                    if dests.len() >= MAX_DESTINATIONS {
+                        drop(dests);
+                        eprintln!(
+                            "Max destination clients reached. Dropping managed destination {addr}; retrying in {backoff:?}."
+                        );
+                    } else {
+                        println!("Managed destination {addr} connected.");
+                        let dest = Destination::spawn(stream, addr.clone());
+                        let alive = dest.alive_handle();
+                        dests.push(dest);
+                        drop(dests);
+
+                        while alive.load(Ordering::Relaxed) {
+                            thread::sleep(MANAGED_HEALTH_POLL_INTERVAL);
+                        }
+                        println!("Managed destination {addr} disconnected, resyncing.");
+                    }
+                }
+                Err(e) => {
                     eprintln!(
-                        "Failed to read payload of size {payload_len} from source {address}: {e}. Disconnecting."
+                        "Managed destination {addr} connect failed: {e}. Retrying in {backoff:?}."
                     );
-                    break;
                 }
+            }
+            thread::sleep(backoff);
+            backoff = (backoff * 2).min(MANAGED_BACKOFF_MAX);
+        }
+    });
+}
+
+fn dial_managed(addr: &str) -> io::Result<TcpStream> {
+    let socket_addr: SocketAddr = addr.parse().map_err(|e| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("invalid managed destination address '{addr}': {e}"),
+        )
+    })?;
+    TcpStream::connect_timeout(&socket_addr, MANAGED_CONNECT_TIMEOUT)
+}
 
-                // Create full message from header and payload
-                let full_message = [header_buf.as_slice(), &payload_buf].concat();
+// -------------- Handle source ----------------
+
+// Handles a source client connection
+//
+// Generic over `Read + Write` (rather than tied to `TcpStream`) so the same
+// parse/validate/relay logic runs unchanged whether the source is a plain TCP
+// or Unix socket or a TLS session wrapping one; `set_read_timeout` lives on the
+// raw `Conn` so the timeout is applied by the caller before wrapping in TLS,
+// and the peer address is passed in rather than derived from `stream`.
+fn handle_source_client<S: Read + Write>(
+    mut stream: S,
+    address: String,
+    destinations: Arc<Mutex<Vec<Destination>>>,
+) {
+    println!("Now handling messages from source client: {address}");
 
-                // For sensitive messages, validate the checksum before forwarding
-                if header.is_sensitive() && !header.validate_checksum(&payload_buf) {
+    // The codec is stateless; all buffering lives in `buf` here, fed by
+    // plain `read` calls rather than `read_exact` since a single read is not
+    // guaranteed to land on a message boundary.
+    let mut codec = CtmpCodec::new();
+    let mut buf = BytesMut::with_capacity(8192);
+    let mut read_chunk = [0u8; 8192];
+
+    'connection: loop {
+        // Drain every complete message already sitting in the buffer before
+        // blocking on another read.
+        loop {
+            match codec.decode(&mut buf) {
+                Ok(Some(message)) => relay_message(&address, message, &destinations),
+                Ok(None) => break,
+                Err(CtmpError::ChecksumMismatch) => {
                     eprintln!(
                         "Invalid checksum for sensitive message from {address}. Dropping message."
                     );
-                    continue; // Drop invalid message, wait for the next one
+                    // The bad frame was already consumed by decode(); loop to
+                    // try whatever follows it.
                 }
+                // The header itself can't be trusted at this point, so there's
+                // no way to resync mid-stream; disconnect as before.
+                Err(e) => {
+                    eprintln!("Invalid CTMP header from source {address}: {e}. Disconnecting.");
+                    break 'connection;
+                }
+            }
+        }
 
-                let mut dests = destinations.lock().unwrap();
-                println!(
-                    "Relaying CTMP message of {} bytes from {} to {} destination clients.",
-                    full_message.len(),
-                    address,
-                    dests.len()
-                );
-
-                // Iterate through destination clients and remove disconnected clients
-                dests.retain_mut(|dest_stream| {
-                    match dest_stream.write_all(&full_message) {
-                        Ok(_) => true, // Keep this client
-                        Err(_) => {
-                            println!("Destination client disconnected during broadcast, removing from list.");
-                            false // Remove this client
-                        }
-                    }
-                });
+        match stream.read(&mut read_chunk) {
+            Ok(0) => {
+                println!("Source client {address} disconnected gracefully.");
+                break;
             }
+            Ok(n) => buf.extend_from_slice(&read_chunk[..n]),
             Err(e) => {
                 match e.kind() {
                     // TimedOut occurs if the client sends no data for READ_TIMEOUT_SECS after sending a message
                     // It would be easy to remove this timeout as it is not in the specification
                     // I just wanted to consider it
                     io::ErrorKind::WouldBlock => {
-                        eprintln!("Source client {address} timed out waiting for data. Disconnecting.");
+                        eprintln!(
+                            "Source client {address} timed out waiting for data. Disconnecting."
+                        );
                     }
                     // This error means the client closed the connection gracefully
                     io::ErrorKind::UnexpectedEof => {
@@ -250,12 +782,213 @@ fn handle_source_client(mut stream: TcpStream, destinations: Arc<Mutex<Vec<TcpSt
                     }
                     // Handle all other potential I/O errors.
                     _ => {
-                        eprintln!("Error reading message from source {address}: {e}. Disconnecting.");
+                        eprintln!(
+                            "Error reading message from source {address}: {e}. Disconnecting."
+                        );
                     }
                 }
                 break;
             }
+        }
+    }
+}
+
+/// Fans a successfully decoded message out to every live destination.
+fn relay_message(
+    address: &str,
+    message: codec::Message,
+    destinations: &Arc<Mutex<Vec<Destination>>>,
+) {
+    // Wrap once in an Arc so fanning out to N destinations is N pointer
+    // clones, not N copies of the payload.
+    let full_message = Arc::new(message.to_bytes());
+
+    let mut dests = destinations.lock().unwrap();
+    println!(
+        "Relaying CTMP message of {} bytes from {} to {} destination clients.",
+        full_message.len(),
+        address,
+        dests.len()
+    );
+
+    // Hand the message off to each destination's queue and let its writer
+    // thread drain it independently; a destination whose writer is already
+    // dead, or whose full queue triggers the DisconnectClient policy, just
+    // gets reaped here (under DropOldest a full queue stays connected).
+    dests.retain(|dest| dest.is_alive() && dest.enqueue(&full_message));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::OnceLock;
+
+    use rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer};
+
+    // `std::env::set_var` affects the whole process, and `cargo test` runs
+    // tests in that same process concurrently by default, so any test that
+    // overrides WRITE_TIMEOUT_ENV (or the other *_ENV vars read at
+    // `Destination::spawn` time) has to hold this lock for as long as the
+    // override is in effect.
+    fn env_lock() -> &'static Mutex<()> {
+        static LOCK: OnceLock<Mutex<()>> = OnceLock::new();
+        LOCK.get_or_init(|| Mutex::new(()))
+    }
+
+    fn message(byte: u8) -> Arc<Vec<u8>> {
+        Arc::new(vec![byte])
+    }
+
+    #[test]
+    fn push_and_pop_preserve_fifo_order_below_capacity() {
+        let queue = DestinationQueue::new(2);
+        let metrics = Metrics::new();
+
+        assert!(queue.push(DropPolicy::DropOldest, message(1), &metrics));
+        assert!(queue.push(DropPolicy::DropOldest, message(2), &metrics));
+
+        assert_eq!(queue.pop(Duration::from_millis(10)), Some(message(1)));
+        assert_eq!(queue.pop(Duration::from_millis(10)), Some(message(2)));
+        assert_eq!(metrics.snapshot().1, 0, "nothing should have been dropped");
+    }
+
+    #[test]
+    fn push_exactly_at_capacity_does_not_evict() {
+        let queue = DestinationQueue::new(2);
+        let metrics = Metrics::new();
 
+        assert!(queue.push(DropPolicy::DisconnectClient, message(1), &metrics));
+        assert!(queue.push(DropPolicy::DisconnectClient, message(2), &metrics));
+
+        // Both messages fit exactly at capacity; neither policy should have
+        // kicked in yet.
+        assert_eq!(metrics.snapshot().1, 0);
+        assert_eq!(queue.pop(Duration::from_millis(10)), Some(message(1)));
+        assert_eq!(queue.pop(Duration::from_millis(10)), Some(message(2)));
+    }
+
+    #[test]
+    fn drop_oldest_evicts_the_front_message_once_over_capacity() {
+        let queue = DestinationQueue::new(2);
+        let metrics = Metrics::new();
+
+        assert!(queue.push(DropPolicy::DropOldest, message(1), &metrics));
+        assert!(queue.push(DropPolicy::DropOldest, message(2), &metrics));
+        // Over capacity: message(1) should be evicted to make room.
+        assert!(queue.push(DropPolicy::DropOldest, message(3), &metrics));
+
+        assert_eq!(metrics.snapshot().1, 1, "one message should have been dropped");
+        assert_eq!(queue.pop(Duration::from_millis(10)), Some(message(2)));
+        assert_eq!(queue.pop(Duration::from_millis(10)), Some(message(3)));
+    }
+
+    #[test]
+    fn disconnect_client_rejects_the_new_message_once_over_capacity() {
+        let queue = DestinationQueue::new(2);
+        let metrics = Metrics::new();
+
+        assert!(queue.push(DropPolicy::DisconnectClient, message(1), &metrics));
+        assert!(queue.push(DropPolicy::DisconnectClient, message(2), &metrics));
+        // Over capacity: the policy says to reject the new message and
+        // signal the caller to disconnect the client, leaving the queue
+        // untouched.
+        assert!(!queue.push(DropPolicy::DisconnectClient, message(3), &metrics));
+
+        assert_eq!(metrics.snapshot().1, 1, "one message should have been dropped");
+        assert_eq!(queue.pop(Duration::from_millis(10)), Some(message(1)));
+        assert_eq!(queue.pop(Duration::from_millis(10)), Some(message(2)));
+    }
+
+    #[test]
+    fn pop_returns_none_after_timeout_on_an_empty_queue() {
+        let queue = DestinationQueue::new(2);
+        assert_eq!(queue.pop(Duration::from_millis(10)), None);
+    }
+
+    // Registers a destination that accepts the connection but never reads
+    // from it, so its receive window fills and `write_all` on the other end
+    // eventually blocks past the write deadline. Asserts the server evicts it
+    // instead of letting the writer thread hang forever.
+    #[test]
+    fn slow_destination_is_evicted_within_its_write_deadline() {
+        let _guard = env_lock().lock().unwrap();
+        std::env::set_var(WRITE_TIMEOUT_ENV, "1");
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let relay_side = TcpStream::connect(addr).unwrap();
+        let (never_drained, _) = listener.accept().unwrap();
+
+        let dest = Destination::spawn(Conn::Tcp(relay_side), "slow-test-destination".to_string());
+        let alive = dest.alive_handle();
+
+        // Large enough to exceed typical OS socket buffers so write_all blocks
+        // on the full send instead of completing immediately.
+        let payload = Arc::new(vec![0u8; 8 * 1024 * 1024]);
+        dest.enqueue(&payload);
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while alive.load(Ordering::Relaxed) && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(50));
+        }
+
+        assert!(
+            !alive.load(Ordering::Relaxed),
+            "slow destination was not evicted within its write deadline"
+        );
+
+        std::env::remove_var(WRITE_TIMEOUT_ENV);
+        drop(never_drained);
+    }
+
+    // Registers a TLS destination that completes the TCP accept but never
+    // sends a ClientHello. `StreamOwned::write()` completes outstanding
+    // handshake I/O first, which reads the ClientHello off the raw socket —
+    // so without a read timeout on the destination stream, this hangs the
+    // writer thread indefinitely regardless of the write timeout. Asserts
+    // the server evicts it within the deadline instead.
+    #[test]
+    fn tls_destination_is_evicted_when_the_handshake_never_starts() {
+        let _guard = env_lock().lock().unwrap();
+        std::env::set_var(WRITE_TIMEOUT_ENV, "1");
+
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let cert_der = CertificateDer::from(cert.cert);
+        let key_der = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(
+            cert.signing_key.serialize_der(),
+        ));
+        let tls_config = Arc::new(
+            ServerConfig::builder()
+                .with_no_client_auth()
+                .with_single_cert(vec![cert_der], key_der)
+                .unwrap(),
+        );
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let relay_side = TcpStream::connect(addr).unwrap();
+        let (never_handshaked, _) = listener.accept().unwrap();
+
+        let conn = ServerConnection::new(tls_config).unwrap();
+        let tls_stream = StreamOwned::new(conn, Conn::Tcp(relay_side));
+
+        let dest = Destination::spawn(tls_stream, "slow-tls-test-destination".to_string());
+        let alive = dest.alive_handle();
+
+        let payload = Arc::new(vec![0u8; 1024]);
+        dest.enqueue(&payload);
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while alive.load(Ordering::Relaxed) && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(50));
         }
+
+        assert!(
+            !alive.load(Ordering::Relaxed),
+            "TLS destination was not evicted when its handshake read never completed"
+        );
+
+        std::env::remove_var(WRITE_TIMEOUT_ENV);
+        drop(never_handshaked);
     }
 }
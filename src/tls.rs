@@ -0,0 +1,60 @@
+//! Optional TLS support for the source and destination listeners, built on
+//! `rustls`. Enabled by pointing `WIRESTORM_TLS_CERT`/`WIRESTORM_TLS_KEY` at a
+//! PEM certificate chain and private key; when either is unset the server
+//! stays in plaintext mode.
+
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::path::Path;
+use std::sync::Arc;
+
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::ServerConfig;
+
+const TLS_CERT_ENV: &str = "WIRESTORM_TLS_CERT";
+const TLS_KEY_ENV: &str = "WIRESTORM_TLS_KEY";
+
+/// Builds a `rustls::ServerConfig` from the cert/key paths named by
+/// `WIRESTORM_TLS_CERT`/`WIRESTORM_TLS_KEY`, or returns `None` if TLS hasn't
+/// been configured (either variable missing means plaintext mode).
+pub fn server_config_from_env() -> io::Result<Option<Arc<ServerConfig>>> {
+    let (cert_path, key_path) = match (std::env::var(TLS_CERT_ENV), std::env::var(TLS_KEY_ENV)) {
+        (Ok(cert), Ok(key)) => (cert, key),
+        _ => return Ok(None),
+    };
+
+    let config = build_server_config(&cert_path, &key_path)?;
+    Ok(Some(Arc::new(config)))
+}
+
+fn build_server_config(cert_path: &str, key_path: &str) -> io::Result<ServerConfig> {
+    let certs = load_certs(cert_path)?;
+    let key = load_private_key(key_path)?;
+
+    ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("invalid TLS certificate/key pair: {e}"),
+            )
+        })
+}
+
+fn load_certs(path: impl AsRef<Path>) -> io::Result<Vec<CertificateDer<'static>>> {
+    let file = File::open(path)?;
+    let mut reader = BufReader::new(file);
+    rustls_pemfile::certs(&mut reader).collect::<Result<Vec<_>, _>>()
+}
+
+fn load_private_key(path: impl AsRef<Path>) -> io::Result<PrivateKeyDer<'static>> {
+    let file = File::open(path)?;
+    let mut reader = BufReader::new(file);
+    rustls_pemfile::private_key(&mut reader)?.ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            "no private key found in key file",
+        )
+    })
+}